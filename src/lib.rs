@@ -3,8 +3,15 @@
 use {
     sbpf_asm_macros::set_return_imm,
     solana_program::{
+        account_info::AccountInfo,
+        bpf_loader_upgradeable,
+        entrypoint::{
+            deserialize, MAX_CPI_INSTRUCTION_ACCOUNTS, MAX_CPI_INSTRUCTION_DATA_LEN,
+            MAX_PERMITTED_DATA_INCREASE,
+        },
         log,
-        program_memory::{sol_memcmp, sol_memcpy},
+        program::invoke_signed,
+        program_memory::{sol_memcmp, sol_memcpy, sol_memset},
     },
     std::mem::size_of,
     std::slice::{from_raw_parts, from_raw_parts_mut},
@@ -27,23 +34,51 @@ pub const SIGNER_KEY:       usize = 0x0010;
 pub const SIGNER_LAMPORTS:  usize = 0x0050;
 
 // Buffer offsets
+pub const BUFFER_HEADER:    usize = 0x2868;
 pub const BUFFER_OWNER:     usize = 0x2890;
 pub const BUFFER_SIZE:      usize = 0x28b8;
 pub const BUFFER_LAMPORTS:  usize = 0x28b0;
 pub const BUFFER_AUTH:      usize = 0x28c0;
-pub const BUFFER_DATA:      usize = 0x28e0;
+pub const BUFFER_CURSOR:    usize = 0x28e0; // 8-byte Append cursor, stored right after the authority pubkey
+pub const BUFFER_DATA:      usize = 0x28e8;
+
+// `BUFFER_SIZE` is the account's real data length, measured from where its
+// data actually starts (`BUFFER_AUTH`). Caller-visible payload starts later,
+// at `BUFFER_DATA`, behind the authority/cursor header. Any check against a
+// payload-relative offset (Write's `requested_offset`, Append's `cursor`)
+// must go through `buffer_capacity` below rather than compare to
+// `BUFFER_SIZE` directly, or it'll allow writes up to `BUFFER_HEADER_LEN`
+// bytes past the end of the account data, into the realloc padding.
+pub const BUFFER_HEADER_LEN: usize = BUFFER_DATA - BUFFER_AUTH;
+
+/// Usable payload capacity for a buffer whose account data length (as
+/// stored at `BUFFER_SIZE`) is `buffer_size`. A buffer too short to hold
+/// the header has no capacity.
+fn buffer_capacity(buffer_size: usize) -> usize {
+    buffer_size.saturating_sub(BUFFER_HEADER_LEN)
+}
 
 // Instruction offsets
 pub const IX_MIN_OFFSET:    usize = 0x50c8;
 
+// Finalize is the only instruction that needs more than our 2 fixed
+// accounts, so it gets its own discriminator constant rather than a match
+// arm on the fast path below.
+pub const FINALIZE_DISCRIMINATOR: u8 = 5;
+
 #[no_mangle]
 /// # Safety
 /// Where we're going, we don't need memory safety.
 pub unsafe extern "C" fn entrypoint(input: *mut u8) {
     // 1. Account checks
 
-    // By knowing we have 2 accounts and the signer account is a non-dup,
-    // we can skip checking the buffer account, as it will fail mutability anyway.
+    // Finalize CPIs into the upgradeable loader and needs its full account
+    // set (program, programdata, buffer, spill, sysvars, authority), so it
+    // can't fit the 2-account fast path below. Hand it off to the generic
+    // deserializer before we commit to that assumption.
+    if *input as u64 > 2 {
+        return finalize(input);
+    }
 
     // 1a) Check we have 2 accounts and signer is a nodup mut signer
     if *input as u64 != 2 {
@@ -52,13 +87,23 @@ pub unsafe extern "C" fn entrypoint(input: *mut u8) {
         return;
     }
 
-    // 1b) If we have 2 accounts and signer is non-dup, we can skip checking the buffer
+    // 1b) Check the signer is a nodup mut signer
     if *(input.add(SIGNER_HEADER) as *const u32) != SIG_MUT_NODUP {
         log::sol_log("Missing signer");
         set_return_imm!(1);
         return;
     }
 
+    // 1c) Check the buffer account is writable. Same flag layout as the
+    // signer header above (dup, is_signer, is_writable, executable) — we
+    // used to rely on the runtime faulting on a non-writable buffer, but an
+    // explicit check here makes the failure deterministic and logged.
+    if *(input.add(BUFFER_HEADER) as *const u8).add(2) != 1 {
+        log::sol_log("Buffer not writable");
+        set_return_imm!(1);
+        return;
+    }
+
     // 2. Get IX data offset, Ix data length and discriminator. Allocate signer and buffer authority.
 
     // 2a) Get offset of IX data
@@ -86,6 +131,9 @@ pub unsafe extern "C" fn entrypoint(input: *mut u8) {
     // 1 - Assign
     // 2 - Write
     // 3 - Close
+    // 4 - Realloc
+    // 5 - Finalize (see `finalize`, dispatched before this match on account count)
+    // 6 - Append
 
     // Verify the buffer authority for Write, Assign and Close IXs
     if discriminator > 0 && sol_memcmp(buffer_authority, signer, PUBKEY_LENGTH) != 0 {
@@ -99,6 +147,7 @@ pub unsafe extern "C" fn entrypoint(input: *mut u8) {
         0 => {
             log::sol_log("Init");
             sol_memcpy(buffer_authority, signer, PUBKEY_LENGTH);
+            *(input.add(BUFFER_CURSOR) as *mut u64) = 0u64;
             ix_data_size -= size_of::<u8>(); // Remove 1 for the discriminator
             let ix_data: &[u8] = from_raw_parts(input.add(offset), ix_data_size);
             let buffer_data = from_raw_parts_mut(input.add(BUFFER_DATA), ix_data_size);
@@ -115,11 +164,20 @@ pub unsafe extern "C" fn entrypoint(input: *mut u8) {
             log::sol_log("Write");
             // Get the offset
             ix_data_size -= size_of::<u32>(); // Remove 1 for discriminator and 3 for u24 offset
-            let mut data_offset = *(input.add(offset) as *const u64) as usize;
-            data_offset &= U24_MASK;
-            data_offset += BUFFER_DATA;
+            let mut requested_offset = *(input.add(offset) as *const u64) as usize;
+            requested_offset &= U24_MASK;
             offset += size_of::<u24>(); // Based u24 hack?
 
+            // Bounds-check against the buffer's allocated payload capacity so
+            // a bad offset can't spill into the realloc padding or ix data.
+            let buffer_size = *(input.add(BUFFER_SIZE) as *const u64) as usize;
+            if requested_offset + ix_data_size > buffer_capacity(buffer_size) {
+                log::sol_log("Write out of bounds");
+                set_return_imm!(1);
+                return;
+            }
+
+            let data_offset = BUFFER_DATA + requested_offset;
             let ix_data: &[u8] = from_raw_parts(input.add(offset), ix_data_size);
             let buffer_data = from_raw_parts_mut(input.add(data_offset), ix_data_size);
             sol_memcpy(buffer_data, ix_data, ix_data_size);
@@ -138,9 +196,167 @@ pub unsafe extern "C" fn entrypoint(input: *mut u8) {
             // Set owner to System Program
             std::ptr::write_volatile(input.add(BUFFER_OWNER) as *mut [u8; 32], [0u8; 32]);
         }
+        // 4. REALLOC
+        4 => {
+            log::sol_log("Realloc");
+            let new_len = *(input.add(offset) as *const u64) as usize;
+            let old_len = *(input.add(BUFFER_SIZE) as *const u64) as usize;
+
+            // Direct mapping requires account capacity never shrinks below
+            // what it was when this invocation started.
+            if new_len < old_len {
+                log::sol_log("Realloc cannot shrink below original length");
+                set_return_imm!(1);
+                return;
+            }
+
+            let delta = new_len - old_len;
+            if delta > MAX_PERMITTED_DATA_INCREASE {
+                log::sol_log("Realloc exceeds max permitted data increase");
+                set_return_imm!(1);
+                return;
+            }
+
+            *(input.add(BUFFER_SIZE) as *mut u64) = new_len as u64;
+
+            // `old_len`/`new_len` are measured from `BUFFER_AUTH` (same base
+            // as `BUFFER_SIZE`), so the newly-exposed region is too — not
+            // `BUFFER_DATA`, which is further in behind the header.
+            if delta > 0 {
+                let new_region = from_raw_parts_mut(input.add(BUFFER_AUTH + old_len), delta);
+                sol_memset(new_region, 0, delta);
+            }
+        }
+        // 6. APPEND
+        6 => {
+            log::sol_log("Append");
+            ix_data_size -= size_of::<u8>(); // Remove 1 for the discriminator
+            let cursor = *(input.add(BUFFER_CURSOR) as *const u64) as usize;
+            let buffer_size = *(input.add(BUFFER_SIZE) as *const u64) as usize;
+
+            // Append never grows the buffer: an append past the current
+            // capacity fails, and Realloc must grow it first. `cursor` is
+            // payload-relative, so compare against the capacity
+            // behind the header, not the raw account data length.
+            if cursor + ix_data_size > buffer_capacity(buffer_size) {
+                log::sol_log("Append out of bounds");
+                set_return_imm!(1);
+                return;
+            }
+
+            let ix_data: &[u8] = from_raw_parts(input.add(offset), ix_data_size);
+            let buffer_data = from_raw_parts_mut(input.add(BUFFER_DATA + cursor), ix_data_size);
+            sol_memcpy(buffer_data, ix_data, ix_data_size);
+
+            *(input.add(BUFFER_CURSOR) as *mut u64) = (cursor + ix_data_size) as u64;
+        }
         _ => {
             log::sol_log("Invalid IX");
             set_return_imm!(1);
         }
     }
 }
+
+/// # Safety
+/// Where we're going, we don't need memory safety.
+///
+/// Finalize publishes the buffer's contents by CPI-ing into the BPF
+/// Upgradeable Loader's `Upgrade` instruction. Unlike the fast path above it
+/// can't rely on fixed offsets (the loader's extra accounts come after the
+/// buffer, whose own data length is variable), so it parses accounts with
+/// the standard generic deserializer instead. We also deliberately build the
+/// CPI through `bpf_loader_upgradeable`/`invoke_signed` rather than hand-rolled
+/// `SolInstruction`/`SolAccountMeta` structs: this is the one place chadbuffer
+/// hands authority to another program, and getting that ABI wrong silently is
+/// worse than the allocation the rest of this crate otherwise avoids.
+///
+/// Note this still assumes the caller has already laid the buffer's data out
+/// as `UpgradeableLoaderState::Buffer` expects (loader metadata header, then
+/// program bytes) via Init/Write/Append before calling Finalize — chadbuffer
+/// writes raw bytes and has no notion of the loader's on-chain encoding.
+unsafe fn finalize(input: *mut u8) {
+    let (_program_id, accounts, instruction_data) = deserialize(input);
+
+    if instruction_data.first() != Some(&FINALIZE_DISCRIMINATOR) {
+        log::sol_log("Invalid IX");
+        set_return_imm!(1);
+        return;
+    }
+
+    // [0] authority (signer), [1] buffer, [2] program, [3] program data,
+    // [4] spill, [5] rent sysvar, [6] clock sysvar — the order Finalize takes
+    // its accounts in. They are reordered below into the order the
+    // upgradeable loader's `Upgrade` instruction expects.
+    let accounts: [AccountInfo; 7] = match accounts.try_into() {
+        Ok(accounts) => accounts,
+        Err(_) => {
+            log::sol_log("Finalize requires 7 accounts");
+            set_return_imm!(1);
+            return;
+        }
+    };
+    let [authority, buffer, program, program_data, spill, rent_sysvar, clock_sysvar] = accounts;
+
+    if !authority.is_signer {
+        log::sol_log("Missing authority signature");
+        set_return_imm!(1);
+        return;
+    }
+
+    if !buffer.is_writable {
+        log::sol_log("Buffer not writable");
+        set_return_imm!(1);
+        return;
+    }
+
+    // The buffer's own data is laid out [authority pubkey][append cursor][payload],
+    // same as the fast path's BUFFER_AUTH/BUFFER_CURSOR/BUFFER_DATA split.
+    // Only the authority is checked here. Bounds-check first since
+    // `buffer` is attacker-controlled and may be shorter than a pubkey.
+    let buffer_data = buffer.try_borrow_data().unwrap();
+    let stored_authority = match buffer_data.get(..PUBKEY_LENGTH) {
+        Some(bytes) => bytes,
+        None => {
+            log::sol_log("Buffer not initialized");
+            set_return_imm!(1);
+            return;
+        }
+    };
+    if sol_memcmp(stored_authority, authority.key.as_ref(), PUBKEY_LENGTH) != 0 {
+        log::sol_log("Invalid authority");
+        set_return_imm!(1);
+        return;
+    }
+    drop(buffer_data);
+
+    // Chadbuffer owns this account going into Finalize (that's what lets
+    // Init/Write/Append write its bytes directly), but the loader's `Upgrade`
+    // validation requires the buffer be owned by `bpf_loader_upgradeable`.
+    // As the current owner we can flip that field directly, the same way
+    // `Close` above resets it to the System Program.
+    buffer.assign(&bpf_loader_upgradeable::id());
+
+    let ix = bpf_loader_upgradeable::upgrade(program.key, buffer.key, authority.key, spill.key);
+
+    if ix.accounts.len() > MAX_CPI_INSTRUCTION_ACCOUNTS as usize
+        || ix.data.len() > MAX_CPI_INSTRUCTION_DATA_LEN as usize
+    {
+        log::sol_log("CPI instruction exceeds limits");
+        set_return_imm!(1);
+        return;
+    }
+
+    let cpi_accounts = [
+        program_data,
+        program,
+        buffer,
+        spill,
+        rent_sysvar,
+        clock_sysvar,
+        authority,
+    ];
+    if invoke_signed(&ix, &cpi_accounts, &[]).is_err() {
+        log::sol_log("Upgrade CPI failed");
+        set_return_imm!(1);
+    }
+}